@@ -1,17 +1,16 @@
-use cdchunking::{Chunker, ChunkInput, ZPAQ};
 use rusqlite;
 use rusqlite::{Connection, Transaction};
 use rusqlite::types::ToSql;
 use sha1::Sha1;
-use std::fs::File;
 use std::path::{Path, PathBuf};
 
 use crate::{Error, HashDigest};
+use crate::chunker::ChunkerAlgorithm;
 
 const SCHEMA: &'static str = "
     CREATE TABLE version(
         name VARCHAR(8) NOT NULL,
-        version VARCHAR(16) NOT NULL
+        version VARCHAR(64) NOT NULL
     );
     INSERT INTO version(name, version) VALUES('rs-sync', '0.1');
 
@@ -26,6 +25,7 @@ const SCHEMA: &'static str = "
         hash VARCHAR(40) NOT NULL,
         file_id INTEGER NOT NULL,
         offset INTEGER NOT NULL,
+        data BLOB,
         PRIMARY KEY(file_id, offset)
     );
     CREATE INDEX idx_blocks_hash ON blocks(hash);
@@ -33,39 +33,146 @@ const SCHEMA: &'static str = "
     CREATE INDEX idx_blocks_file_offset ON blocks(file_id, offset);
 ";
 
+/// Below this size, a block's content is stored directly in the index
+/// (in the `blocks.data` column) instead of being fetched from the file
+/// it came from. This avoids a disk seek and, over the network, a whole
+/// request/response round-trip for blocks too small for that to be
+/// worth it.
+pub const INLINE_THRESHOLD: usize = 3072;
+
+/// Where to find the content of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockLocation {
+    /// The block's content is stored inline in the index.
+    Inline(Vec<u8>),
+    /// The block's content must be read from the given file at the
+    /// given offset.
+    OnDisk(PathBuf, usize),
+}
+
 /// Index of files and blocks
 pub struct Index {
     db: Connection,
+    chunker: ChunkerAlgorithm,
 }
 
 impl Index {
-    /// Open an index from a file
+    /// Open an index from a file, chunking new files with
+    /// `ChunkerAlgorithm::default()` if the index doesn't exist yet
     pub fn open(filename: &Path) -> Result<Index, Error> {
+        Index::open_with_chunker(filename, ChunkerAlgorithm::default())
+    }
+
+    /// Open an index from a file, chunking new files with `chunker` if
+    /// the index doesn't exist yet
+    ///
+    /// If the index already exists, the algorithm it was created with is
+    /// used instead, so that re-indexing a file never switches schemes
+    /// underneath it. An index predating the `chunker` version row or
+    /// the `blocks.data` column (i.e. created before inline block
+    /// storage) is migrated in place rather than rejected: its chunker
+    /// is assumed to have been ZPAQ with today's defaults, which was the
+    /// only option back then, and `blocks.data` is added as a nullable
+    /// column so existing rows just read back as non-inline.
+    pub fn open_with_chunker(
+        filename: &Path,
+        chunker: ChunkerAlgorithm,
+    ) -> Result<Index, Error>
+    {
         let exists = filename.exists();
         let db = Connection::open(filename)?;
-        if !exists {
+        let chunker = if exists {
+            Index::migrate_blocks_data_column(&db)?;
+            Index::load_chunker(&db)?
+        } else {
             warn!("Database doesn't exist, creating tables...");
             db.execute_batch(SCHEMA)?;
-        }
-        Ok(Index { db })
+            Index::store_chunker(&db, chunker)?;
+            chunker
+        };
+        Ok(Index { db, chunker })
     }
 
-    /// Open an in-memory index
+    /// Open an in-memory index, chunking files with
+    /// `ChunkerAlgorithm::default()`
     pub fn open_in_memory() -> Result<Index, Error> {
+        Index::open_in_memory_with_chunker(ChunkerAlgorithm::default())
+    }
+
+    /// Open an in-memory index, chunking files with `chunker`
+    pub fn open_in_memory_with_chunker(
+        chunker: ChunkerAlgorithm,
+    ) -> Result<Index, Error>
+    {
         let db = Connection::open_in_memory()?;
         db.execute_batch(SCHEMA)?;
-        Ok(Index { db })
+        Index::store_chunker(&db, chunker)?;
+        Ok(Index { db, chunker })
+    }
+
+    /// Read back which chunker an existing index was created with.
+    ///
+    /// Indexes created before this series never recorded a `chunker`
+    /// row at all — ZPAQ with today's defaults was the only option back
+    /// then — so a missing row is treated as that legacy default instead
+    /// of a hard error, and backfilled so future opens don't have to
+    /// guess again.
+    fn load_chunker(db: &Connection) -> Result<ChunkerAlgorithm, Error> {
+        let persisted: Result<String, rusqlite::Error> = db.query_row(
+            "SELECT version FROM version WHERE name = 'chunker';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        );
+        match persisted {
+            Ok(persisted) => ChunkerAlgorithm::from_persisted(&persisted),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let legacy = ChunkerAlgorithm::default();
+                Index::store_chunker(db, legacy)?;
+                Ok(legacy)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store_chunker(db: &Connection, chunker: ChunkerAlgorithm) -> Result<(), Error> {
+        db.execute(
+            "INSERT INTO version(name, version) VALUES('chunker', ?);",
+            &[&chunker.to_persisted()],
+        )?;
+        Ok(())
+    }
+
+    /// Add the `blocks.data` column (inline block storage, introduced
+    /// alongside the `chunker` version row) to an index predating it,
+    /// instead of failing the first time it's queried. Existing rows
+    /// just come back with `data` as `NULL`, i.e. non-inline, which is
+    /// exactly what they were before this column existed.
+    fn migrate_blocks_data_column(db: &Connection) -> Result<(), Error> {
+        let mut stmt = db.prepare("PRAGMA table_info(blocks);")?;
+        let has_data_column = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(1))?
+            .filter_map(Result::ok)
+            .any(|name| name == "data");
+        if !has_data_column {
+            warn!("Index predates inline block storage, adding blocks.data column...");
+            db.execute("ALTER TABLE blocks ADD COLUMN data BLOB;", rusqlite::NO_PARAMS)?;
+        }
+        Ok(())
     }
 
     /// Try to find a block in the indexed files
+    ///
+    /// Returns the block's inline content if it was small enough to be
+    /// stored in the index, otherwise the file and offset it can be read
+    /// back from.
     pub fn get_block(
         &self,
         hash: HashDigest,
-    ) -> Result<Option<(PathBuf, usize)>, Error>
+    ) -> Result<Option<BlockLocation>, Error>
     {
         let mut stmt = self.db.prepare(
             "
-            SELECT files.name, blocks.offset
+            SELECT files.name, blocks.offset, blocks.data
             FROM blocks
             INNER JOIN files ON blocks.file_id = files.file_id
             WHERE blocks.hash = ?;
@@ -74,11 +181,16 @@ impl Index {
         let mut rows = stmt.query(&[&hash as &dyn ToSql])?;
         if let Some(row) = rows.next() {
             let row = row?;
-            let path: String = row.get(0);
-            let path: PathBuf = path.into();
-            let offset: i64 = row.get(1);
-            let offset = offset as usize;
-            Ok(Some((path, offset)))
+            let data: Option<Vec<u8>> = row.get(2);
+            if let Some(data) = data {
+                Ok(Some(BlockLocation::Inline(data)))
+            } else {
+                let path: String = row.get(0);
+                let path: PathBuf = path.into();
+                let offset: i64 = row.get(1);
+                let offset = offset as usize;
+                Ok(Some(BlockLocation::OnDisk(path, offset)))
+            }
         } else {
             Ok(None)
         }
@@ -90,18 +202,16 @@ impl Index {
     ) -> Result<IndexTransaction<'a>, rusqlite::Error>
     {
         let tx = self.db.transaction()?;
-        Ok(IndexTransaction { tx })
+        Ok(IndexTransaction { tx, chunker: self.chunker })
     }
 }
 
 /// A transaction on the index, for safety and performance
 pub struct IndexTransaction<'a> {
     tx: Transaction<'a>,
+    chunker: ChunkerAlgorithm,
 }
 
-const ZPAQ_BITS: usize = 13; // 13 bits = 8 KiB block average
-const MAX_BLOCK_SIZE: usize = 1 << 15; // 32 KiB
-
 impl<'a> IndexTransaction<'a> {
     /// Add a file to the index
     ///
@@ -204,19 +314,29 @@ impl<'a> IndexTransaction<'a> {
     }
 
     /// Add a block to the index
+    ///
+    /// If `data` is given, it is stored inline in the index (see
+    /// [`INLINE_THRESHOLD`]) and [`Index::get_block`] will return it
+    /// directly instead of pointing at the file and offset it came from.
     pub fn add_block(
         &mut self,
         hash: HashDigest,
         file_id: u32,
         offset: usize,
+        data: Option<&[u8]>,
     ) -> Result<(), Error>
     {
         self.tx.execute(
             "
-            INSERT INTO blocks(hash, file_id, offset)
-            VALUES(?, ?, ?);
+            INSERT INTO blocks(hash, file_id, offset, data)
+            VALUES(?, ?, ?, ?);
             ",
-            &[&hash as &dyn ToSql, &file_id, &(offset as i64)],
+            &[
+                &hash as &dyn ToSql,
+                &file_id,
+                &(offset as i64),
+                &data.map(|d| d.to_vec()),
+            ],
         )?;
         Ok(())
     }
@@ -227,56 +347,30 @@ impl<'a> IndexTransaction<'a> {
         name: &Path,
     ) -> Result<(), Error>
     {
-        let file = File::open(name)?;
-        let (file_id, up_to_date) = self.add_file(
-            name,
-            file.metadata()?.modified()?.into(),
-        )?;
+        let modified = std::fs::metadata(name)?.modified()?.into();
+        let (file_id, up_to_date) = self.add_file(name, modified)?;
         if !up_to_date {
-            // Use ZPAQ to cut the stream into blocks
-            let chunker = Chunker::new(
-                ZPAQ::new(ZPAQ_BITS) // 13 bits = 8 KiB block average
-            );
-            let mut chunk_iterator = chunker.stream(file);
-            let mut start_offset = 0;
-            let mut offset = 0;
-            let mut sha1 = Sha1::new();
-            while let Some(chunk) = chunk_iterator.read() {
-                match chunk? {
-                    ChunkInput::Data(mut d) => {
-                        while offset - start_offset + d.len()
-                            >= MAX_BLOCK_SIZE
-                        {
-                            let end = MAX_BLOCK_SIZE
-                                + start_offset - offset;
-                            sha1.update(&d[0..end]);
-                            let digest = HashDigest(sha1.digest().bytes());
-                            debug!(
-                                "Max block size reached, adding block, \
-                                 offset={}, size={}, sha1={}",
-                                start_offset, offset + end - start_offset, sha1.digest(),
-                            );
-                            self.add_block(digest, file_id, start_offset)?;
-                            offset += end;
-                            start_offset = offset;
-                            d = &d[end..];
-                            sha1.reset();
-                        }
-                        sha1.update(d);
-                        offset += d.len();
-                    }
-                    ChunkInput::End => {
-                        let digest = HashDigest(sha1.digest().bytes());
-                        debug!(
-                            "Adding block, offset={}, size={}, sha1={}",
-                            start_offset, offset - start_offset, sha1.digest(),
-                        );
-                        self.add_block(digest, file_id, start_offset)?;
-                        start_offset = offset;
-                        sha1.reset();
-                    }
-                }
-            }
+            // Stream the file through the chunker rather than reading it
+            // whole: a block-sync tool exists to handle large files, so
+            // memory use should track the chunker's own max block size,
+            // not the size of whatever file is being indexed.
+            let file = std::fs::File::open(name)?;
+            let chunker = self.chunker;
+            chunker.stream(file, |start_offset, chunk| {
+                let mut sha1 = Sha1::new();
+                sha1.update(chunk);
+                let digest = HashDigest(sha1.digest().bytes());
+                debug!(
+                    "Adding block, offset={}, size={}, sha1={}",
+                    start_offset, chunk.len(), sha1.digest(),
+                );
+                let inline = if chunk.len() < INLINE_THRESHOLD {
+                    Some(chunk)
+                } else {
+                    None
+                };
+                self.add_block(digest, file_id, start_offset, inline)
+            })?;
         }
         Ok(())
     }
@@ -290,10 +384,12 @@ impl<'a> IndexTransaction<'a> {
 #[cfg(test)]
 mod tests {
     use std::io::Write;
+    use sha1::Sha1;
     use tempfile::NamedTempFile;
 
     use crate::HashDigest;
-    use super::{Index, MAX_BLOCK_SIZE};
+    use crate::chunker::ChunkerAlgorithm;
+    use super::{BlockLocation, Index};
 
     #[test]
     fn test() {
@@ -321,7 +417,7 @@ mod tests {
         )).expect("get");
         assert_eq!(
             block1,
-            Some((file.path().into(), 0)),
+            Some(BlockLocation::OnDisk(file.path().into(), 0)),
         );
         let block2 = index.get_block(HashDigest(
             *b"\x57\x0d\x8b\x30\xfc\xfd\x58\x5e\x41\x27\
@@ -329,7 +425,7 @@ mod tests {
         )).expect("get");
         assert_eq!(
             block2,
-            Some((file.path().into(), 11579)),
+            Some(BlockLocation::OnDisk(file.path().into(), 11579)),
         );
         let block3 = index.get_block(HashDigest(
             *b"\xb9\xa8\xc2\x64\x1a\xf2\xcf\x8f\xd8\xf3\
@@ -337,8 +433,44 @@ mod tests {
         )).expect("get");
         assert_eq!(
             block3,
-            Some((file.path().into(), 44347)),
+            Some(BlockLocation::OnDisk(file.path().into(), 44347)),
+        );
+        let offset = |block: Option<BlockLocation>| match block {
+            Some(BlockLocation::OnDisk(_, offset)) => offset,
+            other => panic!("expected an on-disk block, got {:?}", other),
+        };
+        let max_block_size = match ChunkerAlgorithm::default() {
+            ChunkerAlgorithm::Zpaq { max_size, .. } => max_size,
+            other => panic!("expected the default to be ZPAQ, got {:?}", other),
+        };
+        assert_eq!(offset(block3) - offset(block2), max_block_size);
+    }
+
+    #[test]
+    fn test_small_file_is_stored_inline() {
+        let mut file = NamedTempFile::new().expect("tempfile");
+        let content = b"small file, well under the inline threshold";
+        file.write_all(content).expect("tempfile");
+        file.flush().expect("tempfile");
+
+        // FastCDC's min_size keeps it from ever cutting this early, so a
+        // file this small is guaranteed to come back as exactly one
+        // chunk regardless of its content.
+        let mut index = Index::open_in_memory_with_chunker(ChunkerAlgorithm::fastcdc())
+            .expect("db");
+        {
+            let mut tx = index.transaction().expect("db");
+            tx.index_file(file.path()).expect("index");
+            tx.commit().expect("db");
+        }
+
+        let mut sha1 = Sha1::new();
+        sha1.update(content);
+        let hash = HashDigest(sha1.digest().bytes());
+
+        assert_eq!(
+            index.get_block(hash).expect("get"),
+            Some(BlockLocation::Inline(content.to_vec())),
         );
-        assert_eq!(block3.unwrap().1 - block2.unwrap().1, MAX_BLOCK_SIZE);
     }
 }
\ No newline at end of file
@@ -2,6 +2,7 @@ extern crate adler32;
 #[macro_use] extern crate log;
 extern crate sha1;
 
+pub mod chunker;
 mod hasher;
 pub mod utils;
 
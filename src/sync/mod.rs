@@ -21,12 +21,18 @@
 //! The sink will request blocks that are missing from the destination,
 //! which are fed in as they are received.
 
+pub mod compress;
 pub mod fs;
+pub mod net;
+pub mod retry;
 
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::{Error, HashDigest};
 use crate::index::Index;
+use retry::{PendingBlocks, TransferProgress};
 
 /// The sink, representing where the files are being sent.
 ///
@@ -38,7 +44,12 @@ pub trait Sink {
     fn new_file(&mut self, path: &Path, modified: chrono::DateTime<chrono::Utc>) -> Result<(), Error>;
 
     /// Feed entry from the new index
-    fn new_block(&mut self, hash: &HashDigest, size: usize) -> Result<(), Error>;
+    ///
+    /// `inline_data` carries the block's content when it was small enough
+    /// to be stored inline in the index (see `index::INLINE_THRESHOLD`).
+    /// Implementations should write it immediately and must not also
+    /// request it through `next_requested_block`.
+    fn new_block(&mut self, hash: &HashDigest, size: usize, inline_data: Option<&[u8]>) -> Result<(), Error>;
 
     /// Feed a block that was requested
     fn feed_block(&mut self, hash: &HashDigest, block: &[u8]) -> Result<(), Error>;
@@ -51,12 +62,17 @@ pub trait Sink {
 }
 
 /// Events that are received from the index data.
+#[derive(Debug)]
 pub enum IndexEvent {
     /// Start a new file (e.g. next `NewBlock` are blocks of that file)
     NewFile(PathBuf, chrono::DateTime<chrono::Utc>),
 
     /// Add a new block to the current file
-    NewBlock(HashDigest, usize),
+    ///
+    /// The last field carries the block's content when it's small enough
+    /// to have been stored inline in the index, sparing the receiver a
+    /// request/response round-trip for it.
+    NewBlock(HashDigest, usize, Option<Vec<u8>>),
 
     /// End of the whole transfer
     End,
@@ -92,9 +108,69 @@ impl<S: Sink> SinkExt for S {
     }
 }
 
-pub fn do_stream<S: Sink, R: Source>(mut recv: S, mut  send: R) -> Result<(), Error> {
+/// Options controlling how a single transfer is carried out.
+///
+/// Block compression (see `sync::compress`) is not one of these: it
+/// happens inside the concrete `Sink`/`Source` that actually crosses a
+/// wire or disk boundary (e.g. `sync::net`'s `NetSink`/`NetSource`), so
+/// it's configured where those are constructed, not here. `do_stream`
+/// only ever sees the plaintext blocks a transport has already decoded.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferOptions {
+    /// How long to wait for a requested block before re-requesting it.
+    pub block_timeout: Duration,
+
+    /// How many times a block may be re-requested before `do_stream` gives
+    /// up and returns an `Error`.
+    pub max_block_retries: u32,
+
+    /// Base delay for the exponential backoff applied between retries of
+    /// the same block.
+    pub retry_backoff_base: Duration,
+}
+
+impl Default for TransferOptions {
+    fn default() -> TransferOptions {
+        TransferOptions {
+            block_timeout: Duration::from_secs(30),
+            max_block_retries: 5,
+            retry_backoff_base: Duration::from_millis(200),
+        }
+    }
+}
+
+pub fn do_stream<S: Sink, R: Source, P: TransferProgress>(
+    mut recv: S,
+    mut send: R,
+    options: TransferOptions,
+    mut progress: P,
+) -> Result<(), Error> {
+    let mut pending = PendingBlocks::new(
+        options.block_timeout,
+        options.max_block_retries,
+        options.retry_backoff_base,
+    );
     let mut instructions = true;
-    while instructions || recv.is_missing_blocks()? {
+    while instructions || recv.is_missing_blocks()? || !pending.is_empty() {
+        // Re-request whatever timed out since the last time round, backing
+        // off a bit more each time, and give up on a block only once it's
+        // exhausted its retries.
+        for outcome in pending.due_for_retry() {
+            match outcome {
+                Ok((hash, attempt)) => {
+                    progress.block_retry(&hash, attempt);
+                    send.request_block(&hash)?;
+                }
+                Err(retry::BlockFailed(hash, attempts)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("block {:?} timed out after {} attempts", hash, attempts),
+                    ).into());
+                }
+            }
+        }
+        progress.blocks_remaining(pending.len());
+
         // Things are done in order so that bandwidth is used in a smart way
         // For example, if you block on sending block data, you will have
         // received more block requests in the next loop, and you'll only
@@ -103,19 +179,50 @@ pub fn do_stream<S: Sink, R: Source>(mut recv: S, mut  send: R) -> Result<(), Er
         if let Some(hash) = recv.next_requested_block()? {
             // Block requests
             send.request_block(&hash)?; // can block on HTTP receiver side
+            pending.insert(hash);
         } else if let Some((hash, block)) =
             send.get_next_block()? // blocks on receiver side
         {
-            // Block data
-            recv.feed_block(&hash, &block)?; // blocks on sender side
+            // Block data. `get_next_block()` already decompressed this if
+            // the transport compresses blocks on the wire (see
+            // `sync::compress` and e.g. `net::NetSource`), so `block` is
+            // always plain bytes by the time it gets here.
+            if retry::verify_block(&hash, &block) {
+                pending.remove(&hash);
+                recv.feed_block(&hash, &block)?; // blocks on sender side
+            } else {
+                // Corrupted in transit (or a mismatched reply): treat like a
+                // timeout rather than handing bad data to the sink.
+                warn!("Block {:?} failed SHA1 verification, retrying", hash);
+                match pending.force_retry(&hash) {
+                    Ok(retry::ForceRetryOutcome::Retry(attempt)) => {
+                        progress.block_retry(&hash, attempt);
+                        send.request_block(&hash)?;
+                    }
+                    Ok(retry::ForceRetryOutcome::AlreadySatisfied) => {
+                        // Not tracked any more: a duplicate reply for a
+                        // hash whose good copy already arrived (or that
+                        // was never requested). The wire protocol can't
+                        // cancel the in-flight duplicate request this
+                        // came from, so there's nothing to re-request —
+                        // just drop it instead of retrying forever.
+                    }
+                    Err(retry::BlockFailed(hash, attempts)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("block {:?} failed verification after {} attempts", hash, attempts),
+                        ).into());
+                    }
+                }
+            }
         } else if let Some(event) = send.next_from_index()? {
             // Index instructions
             match event {
                 IndexEvent::NewFile(path, modified) => {
                     recv.new_file(&path, modified)?
                 }
-                IndexEvent::NewBlock(hash, size) => {
-                    recv.new_block(&hash, size)?
+                IndexEvent::NewBlock(hash, size, inline_data) => {
+                    recv.new_block(&hash, size, inline_data.as_deref())?
                 }
                 IndexEvent::End => {
                     instructions = false;
@@ -0,0 +1,282 @@
+//! Retrying block fetches that time out or come back corrupted.
+//!
+//! `do_stream` used to assume every `request_block`/`get_next_block`
+//! round-trip would eventually succeed, which doesn't hold over a real
+//! network. [`PendingBlocks`] tracks every block that's been requested
+//! but not yet delivered, with a deadline and a retry counter per block;
+//! [`do_stream`](super::do_stream) re-requests whatever times out, with
+//! exponential backoff, and gives up only once a block has exhausted its
+//! retries.
+
+use std::time::{Duration, Instant};
+
+use sha1::Sha1;
+
+use crate::HashDigest;
+
+/// How a block fetch is going, reported by [`do_stream`](super::do_stream)
+/// as it runs so a caller can show progress.
+pub trait TransferProgress {
+    /// Called once per pass of `do_stream`'s loop with the number of
+    /// blocks still in flight, whether or not it changed since the last
+    /// call.
+    fn blocks_remaining(&mut self, _remaining: usize) {}
+
+    /// Called just before a block is re-requested.
+    fn block_retry(&mut self, _hash: &HashDigest, _attempt: u32) {}
+}
+
+/// A [`TransferProgress`] that reports nothing, for callers that don't
+/// care.
+impl TransferProgress for () {}
+
+struct PendingBlock {
+    hash: HashDigest,
+    requested_at: Instant,
+    retry_at: Instant,
+    retries: u32,
+}
+
+/// Tracks blocks that have been requested but not yet delivered.
+pub struct PendingBlocks {
+    timeout: Duration,
+    max_retries: u32,
+    backoff_base: Duration,
+    blocks: Vec<PendingBlock>,
+}
+
+/// A block exhausted its retries without being delivered correctly.
+#[derive(Debug)]
+pub struct BlockFailed(pub HashDigest, pub u32);
+
+/// What to do after [`PendingBlocks::force_retry`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ForceRetryOutcome {
+    /// The block is still tracked; re-request it (this is attempt number
+    /// `N`).
+    Retry(u32),
+    /// `hash` wasn't tracked — either it was never `insert`ed, or (more
+    /// likely) a valid reply for it already arrived and `remove`d it
+    /// before this one, a duplicate from the race `force_retry` itself
+    /// exists to handle, showed up. There's nothing to retry: the wire
+    /// protocol has no way to cancel an in-flight duplicate request, so
+    /// callers must not treat this as grounds to issue another one.
+    AlreadySatisfied,
+}
+
+impl PendingBlocks {
+    pub fn new(timeout: Duration, max_retries: u32, backoff_base: Duration) -> PendingBlocks {
+        PendingBlocks {
+            timeout,
+            max_retries,
+            backoff_base,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Record that `hash` was just requested.
+    pub fn insert(&mut self, hash: HashDigest) {
+        let now = Instant::now();
+        self.blocks.push(PendingBlock {
+            hash,
+            requested_at: now,
+            retry_at: now + self.timeout,
+            retries: 0,
+        });
+    }
+
+    /// A block was delivered and passed verification; stop tracking it.
+    pub fn remove(&mut self, hash: &HashDigest) {
+        self.blocks.retain(|b| &b.hash != hash);
+    }
+
+    /// Back off and bump the retry counter for `hash`, immediately (used
+    /// when a block came back corrupted rather than having timed out).
+    /// Returns `Err` once `hash` has exhausted its retries, and
+    /// `Ok(ForceRetryOutcome::AlreadySatisfied)` if `hash` isn't tracked
+    /// (a stale duplicate reply, most likely) rather than asking the
+    /// caller to request it again.
+    pub fn force_retry(&mut self, hash: &HashDigest) -> Result<ForceRetryOutcome, BlockFailed> {
+        let now = Instant::now();
+        if let Some(block) = self.blocks.iter_mut().find(|b| &b.hash == hash) {
+            block.retries += 1;
+            if block.retries > self.max_retries {
+                let retries = block.retries;
+                let hash = block.hash;
+                self.blocks.retain(|b| &b.hash != &hash);
+                return Err(BlockFailed(hash, retries));
+            }
+            block.requested_at = now;
+            block.retry_at = now + backoff(self.backoff_base, block.retries);
+            Ok(ForceRetryOutcome::Retry(block.retries))
+        } else {
+            Ok(ForceRetryOutcome::AlreadySatisfied)
+        }
+    }
+
+    /// Return every block whose deadline has passed and is due for
+    /// another attempt, bumping their retry counters. A block that has
+    /// exhausted its retries is reported as `Err` and dropped.
+    pub fn due_for_retry(&mut self) -> Vec<Result<(HashDigest, u32), BlockFailed>> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut failed = Vec::new();
+        for block in self.blocks.iter_mut() {
+            if block.retry_at <= now {
+                block.retries += 1;
+                if block.retries > self.max_retries {
+                    failed.push((block.hash, block.retries));
+                } else {
+                    block.requested_at = now;
+                    block.retry_at = now + backoff(self.backoff_base, block.retries);
+                    due.push(Ok((block.hash, block.retries)));
+                }
+            }
+        }
+        if !failed.is_empty() {
+            let failed_hashes: Vec<HashDigest> =
+                failed.iter().map(|(hash, _)| *hash).collect();
+            self.blocks.retain(|b| !failed_hashes.contains(&b.hash));
+            due.extend(failed.into_iter().map(|(hash, retries)| Err(BlockFailed(hash, retries))));
+        }
+        due
+    }
+}
+
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    base * 2u32.saturating_pow(attempt.min(10))
+}
+
+/// Hash `data` and check it matches `expected`, as a defense against a
+/// corrupted or mismatched block being fed into the sink.
+pub fn verify_block(expected: &HashDigest, data: &[u8]) -> bool {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    &HashDigest(sha1.digest().bytes()) == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: HashDigest = HashDigest(*b"12345678901234567890");
+
+    #[test]
+    fn test_backoff_doubles_per_attempt_and_caps() {
+        let base = Duration::from_millis(10);
+        assert_eq!(backoff(base, 1), base * 2);
+        assert_eq!(backoff(base, 2), base * 4);
+        assert_eq!(backoff(base, 3), base * 8);
+        // Capped at attempt 10 so it can't overflow on pathological inputs.
+        assert_eq!(backoff(base, 10), backoff(base, 100));
+    }
+
+    #[test]
+    fn test_verify_block_matches_and_rejects() {
+        let mut sha1 = Sha1::new();
+        sha1.update(b"hello");
+        let digest = HashDigest(sha1.digest().bytes());
+        assert!(verify_block(&digest, b"hello"));
+        assert!(!verify_block(&digest, b"goodbye"));
+    }
+
+    #[test]
+    fn test_not_due_before_timeout() {
+        let mut pending = PendingBlocks::new(Duration::from_secs(30), 5, Duration::from_millis(200));
+        pending.insert(HASH);
+        assert!(pending.due_for_retry().is_empty());
+    }
+
+    #[test]
+    fn test_remove_stops_tracking() {
+        let mut pending = PendingBlocks::new(Duration::from_secs(30), 5, Duration::from_millis(200));
+        pending.insert(HASH);
+        pending.remove(&HASH);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_force_retry_fails_after_max_retries() {
+        let mut pending = PendingBlocks::new(Duration::from_secs(30), 2, Duration::from_millis(1));
+        pending.insert(HASH);
+        assert_eq!(pending.force_retry(&HASH).expect("1st retry"), ForceRetryOutcome::Retry(1));
+        assert_eq!(pending.force_retry(&HASH).expect("2nd retry"), ForceRetryOutcome::Retry(2));
+        let err = pending.force_retry(&HASH).expect_err("3rd retry exhausts retries");
+        assert_eq!(err.0, HASH);
+        assert_eq!(err.1, 3);
+        assert!(pending.is_empty());
+    }
+
+    /// Regression test: a second, corrupted reply for a hash whose good
+    /// copy already arrived (and was `remove`d) — the exact race
+    /// `force_retry` exists to survive — used to return `Ok(0)`, which
+    /// `do_stream` read as "retry attempt 0" and acted on by
+    /// re-requesting a hash nothing is tracking any more, forever.
+    #[test]
+    fn test_force_retry_on_untracked_hash_is_already_satisfied_not_an_error() {
+        let mut pending = PendingBlocks::new(Duration::from_secs(30), 5, Duration::from_millis(200));
+        // Never inserted at all.
+        assert_eq!(
+            pending.force_retry(&HASH).expect("untracked hash"),
+            ForceRetryOutcome::AlreadySatisfied,
+        );
+
+        // Inserted, then already delivered and removed.
+        pending.insert(HASH);
+        pending.remove(&HASH);
+        assert_eq!(
+            pending.force_retry(&HASH).expect("removed hash"),
+            ForceRetryOutcome::AlreadySatisfied,
+        );
+    }
+
+    /// Regression test: `due_for_retry` used to re-require the full fixed
+    /// `timeout` on every retry regardless of the backoff it computed, so
+    /// retries fired at a flat cadence instead of backing off
+    /// exponentially. With a timeout much longer than the backoff, a
+    /// block should become due again after roughly `backoff_base *
+    /// 2^attempt`, not after another full `timeout`.
+    #[test]
+    fn test_due_for_retry_backs_off_instead_of_waiting_the_full_timeout() {
+        let timeout = Duration::from_millis(500);
+        let backoff_base = Duration::from_millis(20);
+        let mut pending = PendingBlocks::new(timeout, 5, backoff_base);
+        pending.insert(HASH);
+
+        let mut last = Instant::now();
+        let mut deltas = Vec::new();
+        for _ in 0..3 {
+            loop {
+                let due = pending.due_for_retry();
+                if !due.is_empty() {
+                    let now = Instant::now();
+                    deltas.push(now.duration_since(last));
+                    last = now;
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        for delta in &deltas {
+            assert!(
+                *delta < timeout,
+                "retry waited for the full timeout instead of backing off: {:?}",
+                deltas,
+            );
+        }
+        assert!(
+            deltas[2] > deltas[0],
+            "retries should back off, not fire at a flat cadence: {:?}",
+            deltas,
+        );
+    }
+}